@@ -1,16 +1,79 @@
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use oauth2::{
-    basic::{BasicClient, BasicTokenResponse},
-    reqwest::async_http_client,
-    AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
-    PkceCodeVerifier, RedirectUrl, Scope, TokenUrl,
+    basic::{BasicErrorResponseType, BasicRevocationErrorResponse, BasicTokenType},
+    AuthType, AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, StandardErrorResponse,
+    StandardRevocableToken, StandardTokenIntrospectionResponse, StandardTokenResponse, TokenUrl,
 };
+use oauth2::reqwest::async_http_client;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
+/// Extra, B2C-specific fields returned alongside the standard OAuth token
+/// response. `oauth2`'s `BasicTokenResponse` has no room for these since
+/// they aren't part of RFC 6749 proper.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenFields {
+    pub id_token: Option<String>,
+}
+impl oauth2::ExtraTokenFields for IdTokenFields {}
+
+pub type AzureAdTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+type AzureAdClient = Client<
+    StandardErrorResponse<BasicErrorResponseType>,
+    AzureAdTokenResponse,
+    BasicTokenType,
+    StandardTokenIntrospectionResponse<IdTokenFields, BasicTokenType>,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+/// Claims we actually care about out of a validated `id_token`. Anything
+/// else B2C puts in the token is simply ignored by `serde`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub aud: String,
+    pub iss: String,
+    pub exp: usize,
+    #[serde(default)]
+    pub nbf: Option<usize>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+    #[serde(default)]
+    pub emails: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenIdConfiguration {
+    issuer: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kid: String,
+    n: String,
+    e: String,
+}
+
 #[derive(Debug)]
 pub struct AuthorizeContext {
     pub pkce_code_verifier: PkceCodeVerifier,
     pub csrf_token: CsrfToken,
+    pub nonce: String,
     pub authorize_url: Url,
 }
 
@@ -19,59 +82,141 @@ impl Clone for AuthorizeContext {
         Self {
             pkce_code_verifier: PkceCodeVerifier::new(self.pkce_code_verifier.secret().clone()),
             csrf_token: self.csrf_token.clone(),
+            nonce: self.nonce.clone(),
             authorize_url: self.authorize_url.clone(),
         }
     }
 }
 
+/// Everything about a single B2C user-flow (sign-up/sign-in, password
+/// reset, profile edit, ...) that differs from any other: its own
+/// authorize/token endpoints, its own issuer, and its own signing keys.
+#[derive(Debug, Clone)]
+struct Policy {
+    auth_url: AuthUrl,
+    token_url: TokenUrl,
+    issuer: String,
+    jwks_uri: Url,
+    jwks_cache: Arc<Mutex<HashMap<String, DecodingKey>>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct AzureAd {
     pub client_id: ClientId,
     pub client_secret: ClientSecret,
     pub redirect_url: Url,
-    pub auth_url: AuthUrl,
-    pub token_url: TokenUrl,
     pub scopes: Vec<String>,
+    policies: HashMap<String, Policy>,
+    default_policy: String,
+    http_client: reqwest::Client,
 }
 
 impl AzureAd {
-    pub fn new(
+    /// `policies` maps a friendly name (what callers pass as `?policy=`) to
+    /// the actual B2C policy/user-flow identifier (e.g. `B2C_1_susi`).
+    /// `default_policy` must be one of `policies`' keys.
+    pub async fn new(
         client_id: String,
         client_secret: String,
         tenant_name: String,
-        policy_name: String,
+        policies: HashMap<String, String>,
+        default_policy: String,
         redirect_url: Url,
         scopes: Vec<String>,
     ) -> Result<Self> {
-        let client_id = ClientId::new(client_id);
-        let client_secret = ClientSecret::new(client_secret);
-        let auth_url = oauth2::AuthUrl::from_url(Url::parse(&format!(
+        if !policies.contains_key(&default_policy) {
+            anyhow::bail!("default policy '{default_policy}' is not among the configured policies");
+        }
+
+        let http_client = reqwest::Client::new();
+
+        let mut resolved = HashMap::with_capacity(policies.len());
+        for (name, policy_name) in policies {
+            resolved.insert(
+                name,
+                Self::discover_policy(&http_client, &tenant_name, &policy_name).await?,
+            );
+        }
+
+        // without offline_access, B2C won't hand out a refresh token at all
+        let mut scopes = scopes;
+        if !scopes.iter().any(|s| s == "offline_access") {
+            scopes.push("offline_access".to_string());
+        }
+
+        Ok(Self {
+            client_id: ClientId::new(client_id),
+            client_secret: ClientSecret::new(client_secret),
+            redirect_url,
+            scopes,
+            policies: resolved,
+            default_policy,
+            http_client,
+        })
+    }
+
+    /// Builds the authorize/token URLs for a B2C policy and fetches its
+    /// OpenID discovery document so `id_token`s issued under it can later be
+    /// verified.
+    async fn discover_policy(
+        http_client: &reqwest::Client,
+        tenant_name: &str,
+        policy_name: &str,
+    ) -> Result<Policy> {
+        let auth_url = AuthUrl::from_url(Url::parse(&format!(
             "https://{}.b2clogin.com/{}.onmicrosoft.com/{}/oauth2/v2.0/authorize",
             tenant_name, tenant_name, policy_name
         ))?);
-        let token_url = oauth2::TokenUrl::from_url(Url::parse(&format!(
+        let token_url = TokenUrl::from_url(Url::parse(&format!(
             "https://{}.b2clogin.com/{}.onmicrosoft.com/{}/oauth2/v2.0/token",
             tenant_name, tenant_name, policy_name
         ))?);
 
-        Ok(Self {
-            client_id,
-            client_secret,
-            redirect_url,
+        let discovery_url = format!(
+            "https://{}.b2clogin.com/{}.onmicrosoft.com/{}/v2.0/.well-known/openid-configuration",
+            tenant_name, tenant_name, policy_name
+        );
+        let discovery = http_client
+            .get(&discovery_url)
+            .send()
+            .await?
+            .json::<OpenIdConfiguration>()
+            .await?;
+        let jwks_uri = Url::parse(&discovery.jwks_uri)?;
+
+        Ok(Policy {
             auth_url,
             token_url,
-            scopes,
+            issuer: discovery.issuer,
+            jwks_uri,
+            jwks_cache: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
-    pub fn create_authorize_context(&mut self) -> AuthorizeContext {
+    fn policy(&self, name: &str) -> Result<&Policy> {
+        self.policies
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown policy '{name}'"))
+    }
+
+    pub fn has_policy(&self, name: &str) -> bool {
+        self.policies.contains_key(name)
+    }
+
+    pub fn default_policy(&self) -> &str {
+        &self.default_policy
+    }
+
+    pub fn create_authorize_context(&mut self, policy: &str) -> Result<AuthorizeContext> {
+        let policy = self.policy(policy)?;
         let (pkce_code_challenge, pkce_code_verifier) = PkceCodeChallenge::new_random_sha256();
+        let nonce = crate::utils::generate_random_string(32);
 
-        let client = BasicClient::new(
+        let client: AzureAdClient = Client::new(
             self.client_id.clone(),
             Some(self.client_secret.clone()),
-            self.auth_url.clone(),
-            Some(self.token_url.clone()),
+            policy.auth_url.clone(),
+            Some(policy.token_url.clone()),
         )
         .set_auth_type(AuthType::RequestBody)
         .set_redirect_uri(RedirectUrl::from_url(self.redirect_url.clone()));
@@ -80,37 +225,150 @@ impl AzureAd {
             .authorize_url(oauth2::CsrfToken::new_random)
             .add_scopes(self.scopes.iter().map(|s| Scope::new(s.clone())))
             .set_pkce_challenge(pkce_code_challenge)
+            .add_extra_param("nonce", &nonce)
             .url();
 
-        AuthorizeContext {
+        Ok(AuthorizeContext {
             pkce_code_verifier,
             csrf_token: csrf_state,
+            nonce,
             authorize_url,
-        }
+        })
     }
 
     pub async fn exchange_code(
         &self,
         code: String,
         context: &AuthorizeContext,
-    ) -> Result<BasicTokenResponse> {
-        let client = BasicClient::new(
+        policy: &str,
+    ) -> Result<(AzureAdTokenResponse, IdTokenClaims)> {
+        let policy_urls = self.policy(policy)?;
+
+        let client: AzureAdClient = Client::new(
             self.client_id.clone(),
             None,
-            self.auth_url.clone(),
-            Some(self.token_url.clone()),
+            policy_urls.auth_url.clone(),
+            Some(policy_urls.token_url.clone()),
         )
         .set_auth_type(AuthType::RequestBody);
 
         let scopes_str = self.scopes.join(" ");
 
-        Ok(client
+        let token = client
             .exchange_code(AuthorizationCode::new(code))
             .set_pkce_verifier(PkceCodeVerifier::new(
                 context.pkce_code_verifier.secret().clone(),
             ))
             .add_extra_param("scope", scopes_str)
             .request_async(async_http_client)
+            .await?;
+
+        let id_token = token
+            .extra_fields()
+            .id_token
+            .as_deref()
+            .ok_or_else(|| anyhow!("token response did not include an id_token"))?;
+        let claims = self.validate_id_token(id_token, policy).await?;
+
+        Ok((token, claims))
+    }
+
+    /// Exchanges a refresh token for a new access token. B2C rotates the
+    /// refresh token on every use, so callers need to persist the new one
+    /// from the response rather than reusing the one passed in here.
+    pub async fn refresh_token(&self, refresh_token: &str, policy: &str) -> Result<AzureAdTokenResponse> {
+        let policy_urls = self.policy(policy)?;
+
+        let client: AzureAdClient = Client::new(
+            self.client_id.clone(),
+            Some(self.client_secret.clone()),
+            policy_urls.auth_url.clone(),
+            Some(policy_urls.token_url.clone()),
+        )
+        .set_auth_type(AuthType::RequestBody);
+
+        Ok(client
+            .exchange_refresh_token(&oauth2::RefreshToken::new(refresh_token.to_string()))
+            .request_async(async_http_client)
             .await?)
     }
+
+    /// Verifies the RS256 signature, issuer, audience and validity window of
+    /// an `id_token` issued under `policy`. Does *not* check `nonce` —
+    /// that's context-specific (tied to the authorize request that started
+    /// the flow), so it's left to the caller to compare against the `nonce`
+    /// it generated.
+    pub async fn validate_id_token(&self, id_token: &str, policy: &str) -> Result<IdTokenClaims> {
+        let policy_urls = self.policy(policy)?;
+
+        let kid = decode_header(id_token)?
+            .kid
+            .ok_or_else(|| anyhow!("id_token header is missing 'kid'"))?;
+        let key = self.decoding_key_for(policy_urls, &kid).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[self.client_id.as_str()]);
+        validation.set_issuer(&[policy_urls.issuer.as_str()]);
+        validation.leeway = 60;
+        validation.validate_nbf = true;
+
+        Ok(decode::<IdTokenClaims>(id_token, &key, &validation)?.claims)
+    }
+
+    /// Tries `id_token` against every configured policy's issuer/JWKS,
+    /// returning the first successful validation. Used where the caller
+    /// doesn't (and can't) know which policy minted the token up front.
+    pub async fn validate_id_token_any(&self, id_token: &str) -> Option<IdTokenClaims> {
+        for policy in self.policies.keys() {
+            if let Ok(claims) = self.validate_id_token(id_token, policy).await {
+                return Some(claims);
+            }
+        }
+
+        None
+    }
+
+    /// Best-effort RFC 7009 revocation. B2C has no dedicated `/revoke`
+    /// endpoint, so we notify the token endpoint instead; callers treat this
+    /// as advisory since `/revoke` always returns 200 regardless of outcome.
+    pub async fn revoke_token(&self, token: &str, token_type_hint: &str, policy: &str) -> Result<()> {
+        let policy_urls = self.policy(policy)?;
+
+        self.http_client
+            .post(policy_urls.token_url.url().clone())
+            .form(&[("token", token), ("token_type_hint", token_type_hint)])
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the `DecodingKey` for `kid`, fetching and caching the JWKS
+    /// document on first use (or after the key set has rotated).
+    async fn decoding_key_for(&self, policy: &Policy, kid: &str) -> Result<DecodingKey> {
+        if let Some(key) = policy.jwks_cache.lock().unwrap().get(kid) {
+            return Ok(key.clone());
+        }
+
+        // cache miss: the signing key may simply have rotated, so refresh
+        // the whole set rather than failing immediately
+        let jwks = self
+            .http_client
+            .get(policy.jwks_uri.clone())
+            .send()
+            .await?
+            .json::<Jwks>()
+            .await?;
+
+        let mut cache = policy.jwks_cache.lock().unwrap();
+        for jwk in jwks.keys {
+            let key = DecodingKey::from_rsa_components(&jwk.n, &jwk.e)?;
+            cache.insert(jwk.kid, key);
+        }
+
+        cache
+            .get(kid)
+            .cloned()
+            .ok_or_else(|| anyhow!("no signing key found for kid '{kid}'"))
+    }
 }
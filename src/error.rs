@@ -18,3 +18,15 @@ impl From<url::ParseError> for AppError<url::ParseError> {
         AppError(StatusCode::INTERNAL_SERVER_ERROR, err)
     }
 }
+
+impl From<anyhow::Error> for AppError<anyhow::Error> {
+    fn from(err: anyhow::Error) -> Self {
+        AppError(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+}
+
+impl From<url::ParseError> for AppError<anyhow::Error> {
+    fn from(err: url::ParseError) -> Self {
+        AppError(StatusCode::INTERNAL_SERVER_ERROR, err.into())
+    }
+}
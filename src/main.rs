@@ -2,23 +2,24 @@
 #![deny(rust_2018_idioms, warnings)]
 
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
     sync::{Arc, Mutex},
-    time::Instant,
 };
 
 use anyhow::Result;
 use axum::{
-    extract::{Form, Query},
+    extract::{ConnectInfo, Form, Query},
     http::StatusCode,
     response::{IntoResponse, Redirect},
     routing::{get, get_service, post},
     Extension, Json, Router,
 };
-use azuread::{AuthorizeContext, AzureAd};
+use azuread::{AuthorizeContext, AzureAd, AzureAdTokenResponse, IdTokenClaims};
 use error::AppError;
-use oauth2::basic::BasicTokenResponse;
+use oauth2::TokenResponse;
 use serde::{Deserialize, Serialize};
+use store::{CodeEntry, CodeStore, InMemoryStore, SqliteStore, StoredAuthorizeContext};
 use tower::ServiceBuilder;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing::trace;
@@ -26,36 +27,105 @@ use url::Url;
 
 mod azuread;
 mod error;
+mod store;
 mod utils;
 
 const DEFAULT_LISTEN_URL: &str = "0.0.0.0:32468";
 const DEVICE_CODE_EXPIRY_IN_SECS: u64 = 60 * 5;
 const DEVICE_CODE_GC_INTERVAL_IN_SECS: u64 = 60 * 2;
+/// Length, in characters, of the opaque `device_code`. This is never shown
+/// to a human so it can be considerably longer (and higher entropy) than
+/// the `user_code`.
+const DEVICE_CODE_LEN: usize = 40;
+/// Default minimum number of seconds a client must wait between polls of
+/// `/token`, per RFC 8628 section 3.2.
+const DEFAULT_POLL_INTERVAL_IN_SECS: u64 = 5;
+/// Amount by which `interval` is increased every time a client polls faster
+/// than it was last told to, per RFC 8628 section 3.5.
+const SLOW_DOWN_INCREMENT_IN_SECS: u64 = 5;
+/// Default number of failed `/login` or `/token` lookups (per IP), or of
+/// failed sign-ins via `/auth/callback` (per code), tolerated before the IP
+/// is locked out or the code is invalidated.
+const DEFAULT_MAX_CODE_ATTEMPTS: u32 = 5;
+/// Default number of seconds an IP stays locked out after tripping
+/// `max_code_attempts`, after which its attempt count resets.
+const DEFAULT_IP_LOCKOUT_COOLDOWN_IN_SECS: u64 = 60 * 5;
 
 #[derive(Deserialize, Clone, Debug)]
 struct Config {
     client_id: String,
     client_secret: String,
     tenant_name: String,
-    policy_name: String,
+    /// Comma-separated `name:policy_id` pairs, e.g.
+    /// `signup:B2C_1_susi,reset:B2C_1_password_reset`. `name` is what
+    /// callers pass as `?policy=`; `policy_id` is the actual B2C user-flow
+    /// identifier.
+    policies: String,
+    /// Which of `policies`' names is used when a request doesn't specify one.
+    default_policy: String,
     site_url: Url,
     code_length: usize,
     listen_url: Option<String>,
     scopes: Option<String>,
+    /// When set, device codes are persisted to this SQLite database instead
+    /// of an in-process `HashMap`, so the service survives restarts and can
+    /// be run behind a load balancer. Expects a `sqlx` connection string,
+    /// e.g. `sqlite://device-codes.db`.
+    database_url: Option<String>,
+    /// Alphabet `user_code`s are drawn from. Defaults to
+    /// [`utils::DEFAULT_USER_CODE_ALPHABET`], which excludes visually
+    /// confusable characters (0/O, 1/I).
+    user_code_alphabet: Option<String>,
+    /// Size of each dash-separated group in a formatted `user_code`, e.g.
+    /// `4` for `ABCD-EFGH`. `0` disables grouping.
+    user_code_group_size: Option<usize>,
+    /// Number of failed lookups tolerated from a single IP (via `/login` or
+    /// `/token`), or of failed sign-ins for a single code (via
+    /// `/auth/callback`), before it's locked out.
+    max_code_attempts: Option<u32>,
+    /// Seconds an IP stays locked out after tripping `max_code_attempts`
+    /// before its attempt count resets and it's let back in.
+    ip_lockout_cooldown_secs: Option<u64>,
 }
 
-#[derive(Clone, Debug)]
-struct CodeEntry {
-    token: Option<BasicTokenResponse>,
-    auth_context: Option<AuthorizeContext>,
-    created_ts: Instant,
+/// Parses `Config::policies` (`"name:policy_id,name:policy_id"`) into a map.
+fn parse_policies(raw: &str) -> Result<HashMap<String, String>> {
+    raw.split(',')
+        .map(|pair| {
+            let (name, policy_id) = pair
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("invalid policy entry '{pair}', expected 'name:policy_id'"))?;
+            Ok((name.trim().to_string(), policy_id.trim().to_string()))
+        })
+        .collect()
 }
 
 #[derive(Clone, Debug)]
 enum CodeTokenStatus {
-    Invalid,
+    /// No entry exists for this `device_code` at all — it was never issued
+    /// (or was already garbage-collected long enough ago that a legitimate
+    /// client wouldn't still be polling it). Reported as `invalid_grant`.
+    NotFound,
+    /// The code was issued but has aged past `DEVICE_CODE_EXPIRY_IN_SECS`.
+    /// Reported as `expired_token`, per RFC 8628 section 3.5.
+    Expired,
+    Denied,
+    /// Too many failed token exchanges were made against this code (see
+    /// `CodeEntry::locked`). Reported as `invalid_grant`, not `access_denied`
+    /// — the user never rejected anything.
+    Locked,
+    SlowDown,
     Pending,
-    Complete(BasicTokenResponse),
+    Complete(AzureAdTokenResponse),
+}
+
+/// Failed-lookup bookkeeping for a single IP, keyed in `State::ip_attempts`.
+#[derive(Clone, Copy, Debug, Default)]
+struct IpAttempts {
+    count: u32,
+    /// Set once `count` crosses `max_code_attempts`; cleared (along with
+    /// `count`) after `ip_lockout_cooldown_secs` have passed.
+    locked_since: Option<i64>,
 }
 
 #[derive(Clone, Debug)]
@@ -63,80 +133,213 @@ struct State {
     azure_ad: AzureAd,
     site_url: Url,
     code_length: usize,
-    code_map: Arc<Mutex<HashMap<String, CodeEntry>>>,
+    store: Arc<dyn CodeStore>,
+    user_code_alphabet: String,
+    user_code_group_size: usize,
+    max_code_attempts: u32,
+    ip_lockout_cooldown_secs: u64,
+    /// Failed-lookup counts for `/login`/`/token` requests, keyed by client
+    /// IP. Intentionally process-lifetime only, same as `InMemoryStore`.
+    ip_attempts: Arc<Mutex<HashMap<IpAddr, IpAttempts>>>,
 }
 
 impl State {
-    fn new(config: Config) -> Result<Self> {
+    async fn new(config: Config) -> Result<Self> {
         let azure_ad = AzureAd::new(
             config.client_id,
             config.client_secret,
             config.tenant_name,
-            config.policy_name,
+            parse_policies(&config.policies)?,
+            config.default_policy,
             config.site_url.join("/auth/callback")?,
             config
                 .scopes
                 .map(|s| s.split(' ').map(String::from).collect())
                 .unwrap_or_default(),
-        )?;
+        )
+        .await?;
+
+        let store: Arc<dyn CodeStore> = match config.database_url {
+            Some(database_url) => Arc::new(SqliteStore::new(&database_url).await?),
+            None => Arc::new(InMemoryStore::default()),
+        };
 
         Ok(Self {
             azure_ad,
             site_url: config.site_url,
             code_length: config.code_length,
-            code_map: Arc::new(Mutex::new(HashMap::new())),
+            store,
+            user_code_alphabet: config
+                .user_code_alphabet
+                .unwrap_or_else(|| utils::DEFAULT_USER_CODE_ALPHABET.to_string()),
+            user_code_group_size: config
+                .user_code_group_size
+                .unwrap_or(utils::DEFAULT_USER_CODE_GROUP_SIZE),
+            max_code_attempts: config.max_code_attempts.unwrap_or(DEFAULT_MAX_CODE_ATTEMPTS),
+            ip_lockout_cooldown_secs: config
+                .ip_lockout_cooldown_secs
+                .unwrap_or(DEFAULT_IP_LOCKOUT_COOLDOWN_IN_SECS),
+            ip_attempts: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
-    fn garbage_collect(&mut self) {
-        let mut code_map = self.code_map.lock().unwrap();
+    async fn garbage_collect(&self) -> Result<()> {
+        self.store.retain_unexpired(DEVICE_CODE_EXPIRY_IN_SECS).await
+    }
 
-        // remove all expired items by retaining only non-expired items
-        code_map.retain(|_, e| e.created_ts.elapsed().as_secs() < DEVICE_CODE_EXPIRY_IN_SECS);
+    /// Resolves a caller-supplied `?policy=` name to one this `AzureAd` is
+    /// actually configured with, falling back to the default policy when
+    /// none was given or the name is unrecognized.
+    fn resolve_policy(&self, requested: Option<String>) -> String {
+        match requested {
+            Some(name) if self.azure_ad.has_policy(&name) => name,
+            _ => self.azure_ad.default_policy().to_string(),
+        }
     }
 
-    fn add_new_code(&mut self) -> String {
-        let mut code_map = self.code_map.lock().unwrap();
+    /// Whether `ip` has already racked up `max_code_attempts` failed
+    /// `/login`/`/token` lookups and should be turned away without doing
+    /// any further lookups. A lockout clears itself (and the attempt count
+    /// resets) once `ip_lockout_cooldown_secs` have elapsed, so this is a
+    /// cooldown rather than a permanent ban.
+    fn ip_is_locked_out(&self, ip: IpAddr) -> bool {
+        let mut ip_attempts = self.ip_attempts.lock().unwrap();
+        let Some(attempts) = ip_attempts.get(&ip) else {
+            return false;
+        };
+
+        match attempts.locked_since {
+            Some(locked_since) if store::now_ts() - locked_since >= self.ip_lockout_cooldown_secs as i64 => {
+                ip_attempts.remove(&ip);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
 
-        // generate a unique unused device code
-        let mut code = utils::generate_random_string(self.code_length);
-        while code_map.contains_key(&code) {
-            code = utils::generate_random_string(self.code_length);
+    /// Records a failed guess from `ip` against `/login` or `/token`,
+    /// tripping the cooldown once it crosses `max_code_attempts`.
+    fn record_ip_attempt(&self, ip: IpAddr) {
+        let mut ip_attempts = self.ip_attempts.lock().unwrap();
+        let attempts = ip_attempts.entry(ip).or_default();
+        attempts.count += 1;
+        if attempts.count >= self.max_code_attempts && attempts.locked_since.is_none() {
+            attempts.locked_since = Some(store::now_ts());
         }
+    }
 
-        code_map.insert(
-            code.clone(),
-            CodeEntry {
-                token: None,
-                auth_context: None,
-                created_ts: Instant::now(),
-            },
-        );
+    /// Clears `ip`'s failed-guess count after a successful `/login` or
+    /// `/token` lookup, so sporadic legitimate failures (e.g. a typo, or
+    /// another client behind the same NAT guessing) don't slowly accumulate
+    /// toward locking out an IP that's actually succeeding.
+    fn record_ip_success(&self, ip: IpAddr) {
+        self.ip_attempts.lock().unwrap().remove(&ip);
+    }
 
-        code
+    /// Records a failed sign-in attempt against a specific, already-resolved
+    /// entry (e.g. a failed token exchange in `auth_callback`), locking it
+    /// once `max_code_attempts` is reached.
+    async fn record_entry_failure(&self, mut entry: CodeEntry) -> Result<()> {
+        entry.failed_attempts += 1;
+        if entry.failed_attempts >= self.max_code_attempts {
+            entry.locked = true;
+        }
+        self.store.update(entry).await
     }
 
-    fn set_code_token(&mut self, code: String, token: BasicTokenResponse) -> bool {
-        match self
-            .code_map
-            .lock()
-            .unwrap()
-            .entry(code)
-            .and_modify(|e| e.token = Some(token))
-        {
-            Entry::Occupied(_) => true,
-            Entry::Vacant(_) => false,
+    /// Creates a new device code / user code pair and returns them as
+    /// `(device_code, user_code)`.
+    async fn add_new_code(&self, policy: String) -> Result<(String, String)> {
+        // the device code is the store's primary key, so it just needs to
+        // be unique
+        let device_code = utils::generate_random_string(DEVICE_CODE_LEN);
+
+        // the user code is what's read aloud / typed in, so it needs to be
+        // unique among the short human-facing codes too
+        let mut user_code = utils::generate_user_code(
+            &self.user_code_alphabet,
+            self.code_length,
+            self.user_code_group_size,
+        );
+        while self.store.find_by_user_code(&user_code).await?.is_some() {
+            user_code = utils::generate_user_code(
+                &self.user_code_alphabet,
+                self.code_length,
+                self.user_code_group_size,
+            );
         }
+
+        self.store
+            .insert(CodeEntry::new(
+                device_code.clone(),
+                user_code.clone(),
+                policy,
+                DEFAULT_POLL_INTERVAL_IN_SECS,
+            ))
+            .await?;
+
+        Ok((device_code, user_code))
     }
 
-    fn get_code_token(&self, code: String) -> CodeTokenStatus {
-        match self.code_map.lock().unwrap().get(&code) {
-            Some(e) => match e.token.as_ref() {
-                Some(t) => CodeTokenStatus::Complete(t.clone()),
-                None => CodeTokenStatus::Pending,
-            },
-            None => CodeTokenStatus::Invalid,
+    async fn complete_code(
+        &self,
+        device_code: &str,
+        token: AzureAdTokenResponse,
+        claims: IdTokenClaims,
+    ) -> Result<bool> {
+        let Some(mut entry) = self.store.get(device_code).await? else {
+            return Ok(false);
+        };
+
+        entry.set_token(&token, claims);
+        self.store.update(entry).await?;
+
+        Ok(true)
+    }
+
+    /// Looks up the status of a device code the way RFC 8628's `/token`
+    /// endpoint needs it: not-found / pending / slowed-down / denied /
+    /// locked / expired / complete, with an explicit expiry check so a code
+    /// isn't treated as valid right up until the next GC sweep removes it.
+    async fn poll_device_token(&self, device_code: &str) -> Result<CodeTokenStatus> {
+        let Some(mut entry) = self.store.get(device_code).await? else {
+            return Ok(CodeTokenStatus::NotFound);
+        };
+
+        if store::now_ts() - entry.created_ts >= DEVICE_CODE_EXPIRY_IN_SECS as i64 {
+            return Ok(CodeTokenStatus::Expired);
+        }
+
+        if entry.denied {
+            return Ok(CodeTokenStatus::Denied);
+        }
+
+        if entry.locked {
+            return Ok(CodeTokenStatus::Locked);
         }
+
+        let now = store::now_ts();
+        let too_soon = entry
+            .last_polled
+            .map(|last| now - last < entry.interval as i64)
+            .unwrap_or(false);
+
+        if too_soon {
+            entry.interval += SLOW_DOWN_INCREMENT_IN_SECS;
+            entry.last_polled = Some(now);
+            self.store.update(entry).await?;
+            return Ok(CodeTokenStatus::SlowDown);
+        }
+
+        entry.last_polled = Some(now);
+        let status = match entry.token.clone() {
+            Some(token) => CodeTokenStatus::Complete(token.into_response()),
+            None => CodeTokenStatus::Pending,
+        };
+        self.store.update(entry).await?;
+
+        Ok(status)
     }
 }
 
@@ -154,14 +357,17 @@ async fn main() -> Result<()> {
         .unwrap_or(DEFAULT_LISTEN_URL)
         .parse()?;
 
-    let state = State::new(config)?;
+    let state = State::new(config).await?;
 
     let app = Router::new()
         .route("/", get(|| async { Redirect::to("/device.html") }))
         .route("/code", get(generate_code))
         .route("/login", post(login))
         .route("/auth/callback", get(auth_callback))
-        .route("/poll-token", get(poll_token))
+        .route("/device_authorization", post(device_authorization))
+        .route("/token", post(token))
+        .route("/introspect", post(introspect))
+        .route("/revoke", post(revoke))
         .fallback(get_service(ServeDir::new("www")).handle_error(handle_error))
         .layer(
             ServiceBuilder::new()
@@ -173,7 +379,7 @@ async fn main() -> Result<()> {
     tokio::spawn(run_code_gc(state));
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 
@@ -184,9 +390,11 @@ async fn handle_error(_err: ::std::io::Error) -> impl IntoResponse {
     (StatusCode::INTERNAL_SERVER_ERROR, "I/O error")
 }
 
-async fn run_code_gc(mut state: State) {
+async fn run_code_gc(state: State) {
     loop {
-        state.garbage_collect();
+        if let Err(err) = state.garbage_collect().await {
+            trace!("device code garbage collection failed: {err:#}");
+        }
         tokio::time::sleep(std::time::Duration::from_secs(
             DEVICE_CODE_GC_INTERVAL_IN_SECS,
         ))
@@ -200,114 +408,443 @@ struct CodeResponse {
     url: Url,
 }
 
+#[derive(Debug, Deserialize)]
+struct PolicyQuery {
+    #[serde(default)]
+    policy: Option<String>,
+}
+
 async fn generate_code(
-    Extension(mut state): Extension<State>,
-) -> Result<Json<CodeResponse>, AppError<url::ParseError>> {
-    let code = state.add_new_code();
+    Extension(state): Extension<State>,
+    Query(query): Query<PolicyQuery>,
+) -> Result<Json<CodeResponse>, AppError<anyhow::Error>> {
+    let policy = state.resolve_policy(query.policy);
+    let (_, user_code) = state.add_new_code(policy).await?;
 
     Ok(Json(CodeResponse {
-        code,
+        code: user_code,
         url: state.site_url.join("/device.html")?,
     }))
 }
 
+#[derive(Debug, Serialize, Clone)]
+struct DeviceAuthorizationResponse {
+    device_code: String,
+    user_code: String,
+    verification_uri: Url,
+    verification_uri_complete: Url,
+    expires_in: u64,
+    interval: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorizationRequest {
+    #[serde(default)]
+    policy: Option<String>,
+}
+
+/// `POST /device_authorization` — RFC 8628 section 3.1/3.2. This is the
+/// endpoint a standard device-code OAuth client (e.g. the `oauth2` crate's
+/// device flow) talks to instead of the bespoke `/code` + `/login` pair.
+async fn device_authorization(
+    Extension(state): Extension<State>,
+    Form(req): Form<DeviceAuthorizationRequest>,
+) -> Result<Json<DeviceAuthorizationResponse>, AppError<anyhow::Error>> {
+    let policy = state.resolve_policy(req.policy);
+    let (device_code, user_code) = state.add_new_code(policy).await?;
+
+    let verification_uri = state.site_url.join("/device.html")?;
+    let mut verification_uri_complete = verification_uri.clone();
+    verification_uri_complete
+        .query_pairs_mut()
+        .append_pair("user_code", &user_code);
+
+    Ok(Json(DeviceAuthorizationResponse {
+        device_code,
+        user_code,
+        verification_uri,
+        verification_uri_complete,
+        expires_in: DEVICE_CODE_EXPIRY_IN_SECS,
+        interval: DEFAULT_POLL_INTERVAL_IN_SECS,
+    }))
+}
+
 #[derive(Deserialize)]
 struct LoginForm {
     #[serde(rename = "device-code")]
-    device_code: String,
+    user_code: String,
 }
 
-async fn login(Extension(mut state): Extension<State>, Form(login): Form<LoginForm>) -> Redirect {
-    state
-        .code_map
-        .lock()
-        .unwrap()
-        .get_mut(&login.device_code)
-        .map(|entry| {
-            // create authorization context
-            let auth_context = state.azure_ad.create_authorize_context();
+async fn login(
+    Extension(mut state): Extension<State>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(login): Form<LoginForm>,
+) -> Redirect {
+    if state.ip_is_locked_out(addr.ip()) {
+        return Redirect::to("/device.html?error=rate_limited");
+    }
+
+    let entry = state.store.find_by_user_code(&login.user_code).await;
+
+    match entry {
+        Ok(Some(entry)) if entry.denied || entry.locked => {
+            state.record_ip_success(addr.ip());
+            Redirect::to("/device.html?error=invalid_code")
+        }
+        Ok(Some(mut entry)) => {
+            state.record_ip_success(addr.ip());
+
+            // create authorization context, using the policy this code was
+            // issued under
+            let Ok(auth_context) = state.azure_ad.create_authorize_context(&entry.policy) else {
+                return Redirect::to("/device.html?error=invalid_code");
+            };
             let redirect_url = auth_context.authorize_url.as_str().to_string();
-            entry.auth_context = Some(auth_context);
+            entry.auth_context = Some(StoredAuthorizeContext::from(&auth_context));
+
+            if state.store.update(entry).await.is_err() {
+                return Redirect::to("/device.html?error=invalid_code");
+            }
 
             // redirect to Azure AD to get the user to sign in
             Redirect::to(&redirect_url)
-        })
-        .unwrap_or_else(|| {
-            // if we don't have an entry for this code, redirect to the login page
+        }
+        // if we don't have an entry for this code, redirect to the login page
+        _ => {
+            state.record_ip_attempt(addr.ip());
             Redirect::to("/device.html?error=invalid_code")
-        })
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct AuthResponse {
     state: String,
+    #[serde(default)]
     code: String,
+    #[serde(default)]
+    error: String,
 }
 
 async fn auth_callback(
-    Extension(mut state): Extension<State>,
+    Extension(state): Extension<State>,
     Query(auth_response): Query<AuthResponse>,
 ) -> Redirect {
-    // if there's no state or code, we can't do anything
-    if auth_response.state.is_empty() || auth_response.code.is_empty() {
+    // if there's no state, we can't do anything
+    if auth_response.state.is_empty() {
         return Redirect::to("/device.html?error=invalid_response");
     }
 
-    // look for a code map entry which has this csrf token in it
-    let code_entry = state
-        .code_map
-        .lock()
-        .unwrap()
-        .iter_mut()
-        .find(|(_, e)| {
-            e.auth_context
-                .as_ref()
-                .map(|c| *c.csrf_token.secret() == auth_response.state)
-                .is_some()
-        })
-        .map(|(device_code, code_entry)| {
-            (
-                device_code.clone(),
-                // The "expect" call below won't panic because:
-                //  1. We have a lock on "code_map"
-                //  2. We already checked that this entry exists and the csrf token
-                //     matches which wouldn't have passed if this was None.
-                code_entry
-                    .auth_context
-                    .take()
-                    .expect("Auth context should not be None."),
-            )
-        });
-
-    if let Some((device_code, auth_context)) = code_entry {
-        let res = state
-            .azure_ad
-            .exchange_code(auth_response.code, &auth_context)
-            .await;
-
-        if let Ok(token) = res {
-            state.set_code_token(device_code, token);
+    // look for a stored entry which has this csrf token in it
+    let Ok(Some(mut entry)) = state.store.find_by_csrf(&auth_response.state).await else {
+        return Redirect::to("/device.html?error=invalid_response");
+    };
+
+    let Some(stored_context) = entry.auth_context.take() else {
+        return Redirect::to("/device.html?error=invalid_response");
+    };
+    let auth_context: AuthorizeContext = stored_context.into();
+
+    // the user rejected the consent prompt; record it so `/token` reports
+    // "access_denied" instead of leaving the CLI client polling forever
+    if !auth_response.error.is_empty() {
+        entry.denied = true;
+        let _ = state.store.update(entry).await;
+        return Redirect::to("/device.html?error=access_denied");
+    }
+
+    if auth_response.code.is_empty() {
+        return Redirect::to("/device.html?error=invalid_response");
+    }
+
+    let res = state
+        .azure_ad
+        .exchange_code(auth_response.code, &auth_context, &entry.policy)
+        .await;
+
+    match res {
+        // the id_token subsystem already verified signature/issuer/audience/
+        // expiry; the nonce is context-specific so we assert it here
+        Ok((token, claims)) if claims.nonce.as_deref() == Some(auth_context.nonce.as_str()) => {
+            let _ = state.complete_code(&entry.device_code, token, claims).await;
             Redirect::to("/complete.html")
-        } else {
+        }
+        _ => {
+            let _ = state.record_entry_failure(entry).await;
             Redirect::to("/device.html?error=auth_failed")
         }
-    } else {
-        Redirect::to("/device.html?error=invalid_response")
     }
 }
 
+#[derive(Debug, Serialize)]
+struct TokenErrorBody {
+    error: &'static str,
+}
+
+#[derive(Debug)]
+enum TokenError {
+    AuthorizationPending,
+    SlowDown,
+    ExpiredToken,
+    AccessDenied,
+    InvalidRequest,
+    InvalidGrant,
+    UnsupportedGrantType,
+}
+
+impl TokenError {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TokenError::AuthorizationPending => "authorization_pending",
+            TokenError::SlowDown => "slow_down",
+            TokenError::ExpiredToken => "expired_token",
+            TokenError::AccessDenied => "access_denied",
+            TokenError::InvalidRequest => "invalid_request",
+            TokenError::InvalidGrant => "invalid_grant",
+            TokenError::UnsupportedGrantType => "unsupported_grant_type",
+        }
+    }
+}
+
+impl IntoResponse for TokenError {
+    fn into_response(self) -> axum::response::Response {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(TokenErrorBody {
+                error: self.as_str(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const REFRESH_TOKEN_GRANT_TYPE: &str = "refresh_token";
+
 #[derive(Deserialize)]
-struct PollDeviceCode {
-    code: String,
+struct TokenRequest {
+    grant_type: String,
+    #[serde(default)]
+    device_code: Option<String>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    client_id: String,
+}
+
+/// `POST /token` — dispatches on `grant_type`: RFC 8628 section 3.4/3.5 for
+/// the device-code clients polling until the user finishes (or abandons)
+/// the consent flow, and the standard refresh-token grant for clients
+/// keeping a session alive past the access token's lifetime.
+async fn token(
+    Extension(state): Extension<State>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(req): Form<TokenRequest>,
+) -> Result<Json<AzureAdTokenResponse>, TokenError> {
+    trace!(
+        "token request: grant_type={}, client_id={}",
+        req.grant_type,
+        req.client_id
+    );
+
+    if state.ip_is_locked_out(addr.ip()) {
+        return Err(TokenError::InvalidRequest);
+    }
+
+    match req.grant_type.as_str() {
+        DEVICE_CODE_GRANT_TYPE => {
+            let device_code = req.device_code.ok_or(TokenError::InvalidRequest)?;
+            let status = state
+                .poll_device_token(&device_code)
+                .await
+                .unwrap_or(CodeTokenStatus::NotFound);
+
+            match status {
+                // an unrecognized device_code is what the IP guess-limiter
+                // cares about; a code that simply aged out is not a guess
+                // and shouldn't count against the poller
+                CodeTokenStatus::NotFound => {
+                    state.record_ip_attempt(addr.ip());
+                    Err(TokenError::InvalidGrant)
+                }
+                // any other outcome means device_code was a real, issued
+                // code — forgive whatever unrelated guesses this IP has
+                // racked up against other codes
+                CodeTokenStatus::Expired => {
+                    state.record_ip_success(addr.ip());
+                    Err(TokenError::ExpiredToken)
+                }
+                CodeTokenStatus::Denied => {
+                    state.record_ip_success(addr.ip());
+                    Err(TokenError::AccessDenied)
+                }
+                // too many failed token exchanges against this code, not a
+                // user-initiated denial
+                CodeTokenStatus::Locked => {
+                    state.record_ip_success(addr.ip());
+                    Err(TokenError::InvalidGrant)
+                }
+                CodeTokenStatus::SlowDown => {
+                    state.record_ip_success(addr.ip());
+                    Err(TokenError::SlowDown)
+                }
+                CodeTokenStatus::Pending => {
+                    state.record_ip_success(addr.ip());
+                    Err(TokenError::AuthorizationPending)
+                }
+                CodeTokenStatus::Complete(token) => {
+                    state.record_ip_success(addr.ip());
+                    Ok(Json(token))
+                }
+            }
+        }
+        REFRESH_TOKEN_GRANT_TYPE => {
+            let refresh_token = req.refresh_token.ok_or(TokenError::InvalidRequest)?;
+            refresh(&state, &refresh_token).await
+        }
+        _ => Err(TokenError::UnsupportedGrantType),
+    }
+}
+
+/// Exchanges a previously-issued refresh token for a new access token,
+/// persisting the rotated refresh token B2C hands back (`offline_access`
+/// tokens rotate on every use).
+async fn refresh(
+    state: &State,
+    refresh_token: &str,
+) -> Result<Json<AzureAdTokenResponse>, TokenError> {
+    let mut entry = state
+        .store
+        .find_by_token(refresh_token)
+        .await
+        .ok()
+        .flatten()
+        .ok_or(TokenError::InvalidGrant)?;
+
+    let token = state
+        .azure_ad
+        .refresh_token(refresh_token, &entry.policy)
+        .await
+        .map_err(|_| TokenError::InvalidGrant)?;
+
+    // the refresh response carries its own id_token; re-validate and store
+    // it so `entry.claims` (and the access-token expiry `/introspect`
+    // relies on) describe the token we just got back, not the stale one
+    // from the original sign-in
+    let id_token = token
+        .extra_fields()
+        .id_token
+        .as_deref()
+        .ok_or(TokenError::InvalidGrant)?;
+    let claims = state
+        .azure_ad
+        .validate_id_token(id_token, &entry.policy)
+        .await
+        .map_err(|_| TokenError::InvalidGrant)?;
+
+    entry.set_token(&token, claims);
+    let _ = state.store.update(entry).await;
+
+    Ok(Json(token))
+}
+
+#[derive(Deserialize)]
+struct IntrospectRequest {
+    token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<usize>,
+}
+
+impl IntrospectionResponse {
+    fn inactive() -> Self {
+        Self {
+            active: false,
+            sub: None,
+            scope: None,
+            exp: None,
+        }
+    }
 }
 
-async fn poll_token(
+/// `POST /introspect` — RFC 7662. Tokens we minted ourselves are looked up
+/// directly in the code store; anything else (e.g. a bare `id_token`) falls
+/// back to validating it against the JWKS.
+async fn introspect(
     Extension(state): Extension<State>,
-    Query(poll_code): Query<PollDeviceCode>,
-) -> Result<Json<BasicTokenResponse>, StatusCode> {
-    match state.get_code_token(poll_code.code) {
-        CodeTokenStatus::Invalid => Err(StatusCode::NOT_FOUND),
-        CodeTokenStatus::Pending => Err(StatusCode::NO_CONTENT),
-        CodeTokenStatus::Complete(token) => Ok(Json(token)),
+    Form(req): Form<IntrospectRequest>,
+) -> Json<IntrospectionResponse> {
+    if let Ok(Some(entry)) = state.store.find_by_token(&req.token).await {
+        if !entry.denied {
+            // liveness is judged off the access token's own expiry, not the
+            // id_token's `exp` — that claim goes stale the moment a refresh
+            // hands back a new access token without a fresh sign-in
+            if let Some(expires_at) = entry.access_token_expires_at {
+                if expires_at > store::now_ts() {
+                    if let Some(claims) = entry.claims {
+                        return Json(IntrospectionResponse {
+                            active: true,
+                            sub: Some(claims.sub),
+                            scope: entry.token.and_then(|t| t.scopes).map(|s| s.join(" ")),
+                            exp: Some(expires_at as usize),
+                        });
+                    }
+                }
+            }
+        }
+
+        return Json(IntrospectionResponse::inactive());
+    }
+
+    if let Some(claims) = state.azure_ad.validate_id_token_any(&req.token).await {
+        if claims.exp as i64 > store::now_ts() {
+            return Json(IntrospectionResponse {
+                active: true,
+                sub: Some(claims.sub),
+                scope: None,
+                exp: Some(claims.exp),
+            });
+        }
+    }
+
+    Json(IntrospectionResponse::inactive())
+}
+
+#[derive(Deserialize)]
+struct RevokeRequest {
+    token: String,
+    #[serde(default)]
+    token_type_hint: String,
+}
+
+/// `POST /revoke` — RFC 7009. Always responds 200, even for a token we don't
+/// recognize, per section 2.2 of the RFC.
+async fn revoke(
+    Extension(state): Extension<State>,
+    Form(req): Form<RevokeRequest>,
+) -> StatusCode {
+    trace!("revoke request: token_type_hint={}", req.token_type_hint);
+
+    if let Ok(Some(entry)) = state.store.find_by_token(&req.token).await {
+        if let Some(refresh_token) = entry.token.as_ref().and_then(|t| t.refresh_token.clone()) {
+            if let Err(err) = state
+                .azure_ad
+                .revoke_token(&refresh_token, "refresh_token", &entry.policy)
+                .await
+            {
+                trace!("failed to forward revocation to B2C: {err:#}");
+            }
+        }
+
+        let _ = state.store.remove(&entry.device_code).await;
     }
+
+    StatusCode::OK
 }
@@ -0,0 +1,416 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use oauth2::{basic::BasicTokenType, AccessToken, RefreshToken, Scope, TokenResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, Row};
+use url::Url;
+
+use crate::azuread::{AuthorizeContext, AzureAdTokenResponse, IdTokenClaims, IdTokenFields};
+
+pub(crate) fn now_ts() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the Unix epoch")
+        .as_secs() as i64
+}
+
+/// Serializable mirror of `AzureAdTokenResponse`. The `oauth2` response type
+/// wraps a handful of secret-ish newtypes we don't want to assume round-trip
+/// through every storage backend, so we flatten it down to plain fields
+/// ourselves and rebuild it on the way out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub access_token: String,
+    pub expires_in: Option<u64>,
+    pub refresh_token: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    pub id_token: Option<String>,
+}
+
+impl From<&AzureAdTokenResponse> for StoredToken {
+    fn from(token: &AzureAdTokenResponse) -> Self {
+        Self {
+            access_token: token.access_token().secret().clone(),
+            expires_in: token.expires_in().map(|d| d.as_secs()),
+            refresh_token: token.refresh_token().map(|t| t.secret().clone()),
+            scopes: token
+                .scopes()
+                .map(|scopes| scopes.iter().map(ToString::to_string).collect()),
+            id_token: token.extra_fields().id_token.clone(),
+        }
+    }
+}
+
+impl StoredToken {
+    pub fn into_response(self) -> AzureAdTokenResponse {
+        let mut token = AzureAdTokenResponse::new(
+            AccessToken::new(self.access_token),
+            BasicTokenType::Bearer,
+            IdTokenFields {
+                id_token: self.id_token,
+            },
+        );
+        token.set_refresh_token(self.refresh_token.map(RefreshToken::new));
+        token.set_expires_in(self.expires_in.map(Duration::from_secs).as_ref());
+        token.set_scopes(
+            self.scopes
+                .map(|scopes| scopes.into_iter().map(Scope::new).collect()),
+        );
+
+        token
+    }
+}
+
+/// Serializable mirror of `AuthorizeContext`. Same deal as `StoredToken`:
+/// `PkceCodeVerifier`/`CsrfToken` deliberately don't implement `Serialize`
+/// since they're secrets, so we pull the secret strings out ourselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredAuthorizeContext {
+    pub pkce_code_verifier: String,
+    pub csrf_token: String,
+    pub nonce: String,
+    pub authorize_url: Url,
+}
+
+impl From<&AuthorizeContext> for StoredAuthorizeContext {
+    fn from(ctx: &AuthorizeContext) -> Self {
+        Self {
+            pkce_code_verifier: ctx.pkce_code_verifier.secret().clone(),
+            csrf_token: ctx.csrf_token.secret().clone(),
+            nonce: ctx.nonce.clone(),
+            authorize_url: ctx.authorize_url.clone(),
+        }
+    }
+}
+
+impl From<StoredAuthorizeContext> for AuthorizeContext {
+    fn from(ctx: StoredAuthorizeContext) -> Self {
+        AuthorizeContext {
+            pkce_code_verifier: oauth2::PkceCodeVerifier::new(ctx.pkce_code_verifier),
+            csrf_token: oauth2::CsrfToken::new(ctx.csrf_token),
+            nonce: ctx.nonce,
+            authorize_url: ctx.authorize_url,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodeEntry {
+    /// Opaque, high-entropy code used by the `/token` endpoint. This is the
+    /// store's primary key.
+    pub device_code: String,
+    /// Short code a human types into `/device.html` to approve the request.
+    pub user_code: String,
+    /// Name of the B2C user-flow this code was issued under, e.g. "signup"
+    /// or "password_reset". Resolved once at creation time so `login` and
+    /// `auth_callback` always use the same policy for a given code.
+    pub policy: String,
+    pub token: Option<StoredToken>,
+    /// Unix timestamp the current `token.access_token` expires at. Computed
+    /// from the token response's `expires_in` whenever `token` is set, so
+    /// `/introspect` can judge liveness off the access token itself rather
+    /// than the `id_token`'s `exp` (which goes stale across a refresh).
+    pub access_token_expires_at: Option<i64>,
+    /// Claims from the validated `id_token`, once the user has signed in.
+    pub claims: Option<IdTokenClaims>,
+    pub auth_context: Option<StoredAuthorizeContext>,
+    pub created_ts: i64,
+    /// When this entry was last polled via `/token`, used to enforce `interval`.
+    pub last_polled: Option<i64>,
+    /// Minimum number of seconds between polls; grows when a client ignores it.
+    pub interval: u64,
+    /// Set when the user rejects the consent prompt on the identity provider.
+    pub denied: bool,
+    /// Set once `failed_attempts` crosses `Config::max_code_attempts`. Kept
+    /// separate from `denied`, which means "the user said no" — this means
+    /// "too many failed token exchanges", so `/token` reports it as an
+    /// invalid grant rather than a user-initiated denial.
+    pub locked: bool,
+    /// Number of failed sign-in attempts against this specific code. Once
+    /// this crosses `Config::max_code_attempts`, the entry is invalidated.
+    pub failed_attempts: u32,
+}
+
+impl CodeEntry {
+    pub fn new(device_code: String, user_code: String, policy: String, interval: u64) -> Self {
+        Self {
+            device_code,
+            user_code,
+            policy,
+            token: None,
+            access_token_expires_at: None,
+            claims: None,
+            auth_context: None,
+            created_ts: now_ts(),
+            last_polled: None,
+            interval,
+            denied: false,
+            locked: false,
+            failed_attempts: 0,
+        }
+    }
+
+    /// Stores a freshly-issued or refreshed access token together with the
+    /// claims from its `id_token`, e.g. from the initial sign-in or a
+    /// `refresh_token` grant. Always update both together so `claims` never
+    /// lags behind the access token it's supposed to describe.
+    pub fn set_token(&mut self, token: &AzureAdTokenResponse, claims: IdTokenClaims) {
+        self.access_token_expires_at = token
+            .expires_in()
+            .map(|d| now_ts() + d.as_secs() as i64);
+        self.token = Some(StoredToken::from(token));
+        self.claims = Some(claims);
+    }
+}
+
+/// Storage for in-flight and completed device codes. `InMemoryStore` is the
+/// default, zero-config backend; `SqliteStore` lets the service survive
+/// restarts and be run behind a load balancer.
+#[async_trait]
+pub trait CodeStore: Send + Sync + fmt::Debug {
+    async fn insert(&self, entry: CodeEntry) -> Result<()>;
+    async fn get(&self, device_code: &str) -> Result<Option<CodeEntry>>;
+    async fn find_by_user_code(&self, user_code: &str) -> Result<Option<CodeEntry>>;
+    async fn find_by_csrf(&self, csrf_token: &str) -> Result<Option<CodeEntry>>;
+    /// Looks up the entry holding `token` as either its access or refresh
+    /// token, for `/introspect` and `/revoke`.
+    async fn find_by_token(&self, token: &str) -> Result<Option<CodeEntry>>;
+    /// Writes back a (presumably mutated) entry that was previously read via
+    /// `get`/`find_by_user_code`/`find_by_csrf`. Used for e.g. `set_token`.
+    async fn update(&self, entry: CodeEntry) -> Result<()>;
+    async fn remove(&self, device_code: &str) -> Result<()>;
+    async fn retain_unexpired(&self, expiry_secs: u64) -> Result<()>;
+}
+
+fn token_matches(entry: &CodeEntry, token: &str) -> bool {
+    entry
+        .token
+        .as_ref()
+        .map(|t| t.access_token == token || t.refresh_token.as_deref() == Some(token))
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStore {
+    entries: Arc<Mutex<HashMap<String, CodeEntry>>>,
+}
+
+#[async_trait]
+impl CodeStore for InMemoryStore {
+    async fn insert(&self, entry: CodeEntry) -> Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.device_code.clone(), entry);
+        Ok(())
+    }
+
+    async fn get(&self, device_code: &str) -> Result<Option<CodeEntry>> {
+        Ok(self.entries.lock().unwrap().get(device_code).cloned())
+    }
+
+    async fn find_by_user_code(&self, user_code: &str) -> Result<Option<CodeEntry>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .find(|e| e.user_code == user_code)
+            .cloned())
+    }
+
+    async fn find_by_csrf(&self, csrf_token: &str) -> Result<Option<CodeEntry>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .find(|e| {
+                e.auth_context
+                    .as_ref()
+                    .map(|c| c.csrf_token == csrf_token)
+                    .unwrap_or(false)
+            })
+            .cloned())
+    }
+
+    async fn find_by_token(&self, token: &str) -> Result<Option<CodeEntry>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .find(|e| token_matches(e, token))
+            .cloned())
+    }
+
+    async fn update(&self, entry: CodeEntry) -> Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.device_code.clone(), entry);
+        Ok(())
+    }
+
+    async fn remove(&self, device_code: &str) -> Result<()> {
+        self.entries.lock().unwrap().remove(device_code);
+        Ok(())
+    }
+
+    async fn retain_unexpired(&self, expiry_secs: u64) -> Result<()> {
+        let cutoff = now_ts() - expiry_secs as i64;
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, e| e.created_ts >= cutoff);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn new(database_url: &str) -> Result<Self> {
+        let pool = SqlitePool::connect(database_url).await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS code_entries (
+                device_code TEXT PRIMARY KEY,
+                user_code TEXT NOT NULL,
+                entry_json TEXT NOT NULL,
+                created_ts INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    fn decode_row(row: sqlx::sqlite::SqliteRow) -> Result<CodeEntry> {
+        let json: String = row.try_get("entry_json")?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+#[async_trait]
+impl CodeStore for SqliteStore {
+    async fn insert(&self, entry: CodeEntry) -> Result<()> {
+        let json = serde_json::to_string(&entry)?;
+
+        sqlx::query(
+            "INSERT INTO code_entries (device_code, user_code, entry_json, created_ts)
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(&entry.device_code)
+        .bind(&entry.user_code)
+        .bind(json)
+        .bind(entry.created_ts)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get(&self, device_code: &str) -> Result<Option<CodeEntry>> {
+        sqlx::query("SELECT entry_json FROM code_entries WHERE device_code = ?")
+            .bind(device_code)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(Self::decode_row)
+            .transpose()
+    }
+
+    async fn find_by_user_code(&self, user_code: &str) -> Result<Option<CodeEntry>> {
+        sqlx::query("SELECT entry_json FROM code_entries WHERE user_code = ?")
+            .bind(user_code)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(Self::decode_row)
+            .transpose()
+    }
+
+    async fn find_by_csrf(&self, csrf_token: &str) -> Result<Option<CodeEntry>> {
+        // the csrf token lives inside the JSON-blobbed auth_context, so
+        // unlike the device/user code lookups this has to scan every row
+        let rows = sqlx::query("SELECT entry_json FROM code_entries")
+            .fetch_all(&self.pool)
+            .await?;
+
+        for row in rows {
+            let entry = Self::decode_row(row)?;
+            if entry
+                .auth_context
+                .as_ref()
+                .map(|c| c.csrf_token == csrf_token)
+                .unwrap_or(false)
+            {
+                return Ok(Some(entry));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn find_by_token(&self, token: &str) -> Result<Option<CodeEntry>> {
+        // the access/refresh tokens live inside the JSON-blobbed `token`
+        // field, so this has to scan every row
+        let rows = sqlx::query("SELECT entry_json FROM code_entries")
+            .fetch_all(&self.pool)
+            .await?;
+
+        for row in rows {
+            let entry = Self::decode_row(row)?;
+            if token_matches(&entry, token) {
+                return Ok(Some(entry));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn update(&self, entry: CodeEntry) -> Result<()> {
+        let json = serde_json::to_string(&entry)?;
+
+        sqlx::query(
+            "UPDATE code_entries SET user_code = ?, entry_json = ? WHERE device_code = ?",
+        )
+        .bind(&entry.user_code)
+        .bind(json)
+        .bind(&entry.device_code)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, device_code: &str) -> Result<()> {
+        sqlx::query("DELETE FROM code_entries WHERE device_code = ?")
+            .bind(device_code)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn retain_unexpired(&self, expiry_secs: u64) -> Result<()> {
+        let cutoff = now_ts() - expiry_secs as i64;
+
+        sqlx::query("DELETE FROM code_entries WHERE created_ts < ?")
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -2,6 +2,12 @@ use rand::Rng;
 
 const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
 
+/// Default alphabet for human-entered `user_code`s: excludes characters
+/// that are easily confused when read aloud or typed (0/O, 1/I).
+pub const DEFAULT_USER_CODE_ALPHABET: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+/// Default number of characters per dash-separated group, e.g. `ABCD-EFGH`.
+pub const DEFAULT_USER_CODE_GROUP_SIZE: usize = 4;
+
 pub fn generate_random_string(len: usize) -> String {
     let mut rng = rand::thread_rng();
 
@@ -12,3 +18,24 @@ pub fn generate_random_string(len: usize) -> String {
         })
         .collect()
 }
+
+/// Generates a `len`-character code drawn from `alphabet`, formatted as
+/// dash-separated groups of `group_size` characters (e.g. `ABCD-EFGH`).
+/// `group_size == 0` disables grouping.
+pub fn generate_user_code(alphabet: &str, len: usize, group_size: usize) -> String {
+    let chars: Vec<char> = alphabet.chars().collect();
+    let mut rng = rand::thread_rng();
+
+    let raw: Vec<char> = (0..len)
+        .map(|_| chars[rng.gen_range(0..chars.len())])
+        .collect();
+
+    if group_size == 0 {
+        return raw.into_iter().collect();
+    }
+
+    raw.chunks(group_size)
+        .map(|chunk| chunk.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}